@@ -5,53 +5,227 @@
 
 //! TCP session actor which is managing the specific communication to a node
 
-// TODO: RUSTLS + Tokio : https://github.com/tokio-rs/tls/blob/master/tokio-rustls/examples/server/src/main.rs
-
 use std::convert::TryInto;
 use std::net::SocketAddr;
+use std::sync::Arc;
 
-use bytes::Bytes;
+use bytes::{Bytes, BytesMut};
+use futures::{SinkExt, StreamExt};
 use prost::Message;
 use ractor::{Actor, ActorCell, ActorProcessingErr, ActorRef};
 use ractor::{SpawnErr, SupervisionEvent};
-use tokio::io::AsyncReadExt;
-use tokio::io::AsyncWriteExt;
-use tokio::io::ErrorKind;
-use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use kuska_handshake::{
+    handshake_client, handshake_server, BoxStream, Error as HandshakeError, NetworkKey,
+    PublicKey, SecretKey,
+};
+use tokio::io::AsyncRead;
+use tokio::io::AsyncWrite;
 use tokio::net::TcpStream;
+use tokio_rustls::{rustls, TlsAcceptor, TlsConnector};
+use tokio_util::codec::{FramedRead, FramedWrite, LengthDelimitedCodec};
+use tokio_util::compat::{FuturesAsyncReadCompatExt, TokioAsyncReadCompatExt};
 
 use crate::RactorMessage;
 
-/// Helper method to read exactly `len` bytes from the stream into a pre-allocated buffer
-/// of bytes
-async fn read_n_bytes(stream: &mut OwnedReadHalf, len: usize) -> Result<Vec<u8>, tokio::io::Error> {
-    let mut buf = vec![0u8; len];
-    let mut c_len = 0;
-    stream.readable().await?;
-    while c_len < len {
-        let n = stream.read(&mut buf[c_len..]).await?;
-        if n == 0 {
-            // EOF
-            return Err(tokio::io::Error::new(
-                tokio::io::ErrorKind::UnexpectedEof,
-                "EOF",
-            ));
+/// The reading half of a [Session]'s stream, which may be a plaintext TCP
+/// stream, the read side of a negotiated TLS stream, or the read side of a
+/// Secret-Handshake [BoxStream]
+type SessionReadHalf = Box<dyn AsyncRead + Send + Unpin>;
+
+/// The writing half of a [Session]'s stream, which may be a plaintext TCP
+/// stream, the write side of a negotiated TLS stream, or the write side of a
+/// Secret-Handshake [BoxStream]
+type SessionWriteHalf = Box<dyn AsyncWrite + Send + Unpin>;
+
+/// How a [Session] should secure its underlying [TcpStream], if at all
+pub enum SessionTlsMode {
+    /// This session accepted an inbound connection and should act as the TLS server
+    Server(Arc<rustls::ServerConfig>),
+    /// This session initiated an outbound connection and should act as the TLS client,
+    /// verifying the peer against the given server name
+    Client(Arc<rustls::ClientConfig>, rustls::ServerName),
+}
+
+/// This node's long-term identity plus the cluster-wide shared secret, used to mutually
+/// authenticate a peer via the Secret-Handshake protocol before a [Session] is trusted with
+/// any [SessionMessage] traffic
+pub struct HandshakeConfig {
+    /// This node's long-term Ed25519 public key
+    pub public_key: PublicKey,
+    /// This node's long-term Ed25519 secret key
+    pub secret_key: SecretKey,
+    /// Shared secret identifying this node's cluster; a peer that can't prove knowledge of
+    /// it fails the handshake even with an otherwise valid keypair
+    pub network_key: NetworkKey,
+    /// The long-term public keys of cluster members this node is willing to accept an
+    /// *inbound* connection from. An accepting server doesn't know which member is dialing
+    /// in until the handshake completes, so membership is checked against this set rather
+    /// than a single pinned key
+    pub allowed_peer_public_keys: Vec<PublicKey>,
+    /// For an *outbound* connection, the specific peer this node is dialing and expects to
+    /// authenticate as - the caller already knows exactly who it meant to connect to
+    pub expected_peer_public_key: PublicKey,
+}
+
+/// Which side of the Secret-Handshake a [Session] should play, if the handshake is enabled
+/// at all
+pub enum SessionHandshakeMode {
+    /// This session accepted an inbound connection and should act as the handshake server
+    Server(Arc<HandshakeConfig>),
+    /// This session initiated an outbound connection and should act as the handshake client
+    Client(Arc<HandshakeConfig>),
+}
+
+/// Runs the Secret-Handshake exchange over `stream` as the server side, authenticating the
+/// peer against `config.allowed_peer_public_keys` and deriving symmetric session keys on
+/// success. We don't learn which cluster member is dialing in until the handshake completes,
+/// so membership is checked against the whole allowed set rather than a single pinned key.
+/// `kuska_handshake` speaks the `futures` `AsyncRead`/`AsyncWrite` traits rather than tokio's,
+/// so `stream` is bridged through `tokio_util::compat` for the duration of the handshake and
+/// the resulting [BoxStream] is bridged back before we hand it to the caller
+async fn run_server_handshake<S>(
+    stream: S,
+    config: &HandshakeConfig,
+) -> Result<(impl AsyncRead + Send + Unpin, impl AsyncWrite + Send + Unpin), HandshakeError>
+where
+    S: AsyncRead + AsyncWrite + Send + Unpin,
+{
+    let mut compat = stream.compat();
+    let handshake = handshake_server(
+        &mut compat,
+        config.network_key.clone(),
+        config.public_key.clone(),
+        config.secret_key.clone(),
+    )
+    .await?;
+    if !config
+        .allowed_peer_public_keys
+        .iter()
+        .any(|allowed| *allowed == handshake.peer_pk)
+    {
+        return Err(HandshakeError::InvalidPeerPublicKey);
+    }
+    let boxed = BoxStream::new(compat, handshake, MAX_CHUNK_SIZE).compat();
+    Ok(tokio::io::split(boxed))
+}
+
+/// Runs the Secret-Handshake exchange over `stream` as the client side, verifying the
+/// server's long-term key against `config.expected_peer_public_key` and deriving symmetric
+/// session keys on success. See [run_server_handshake] for why `stream` is bridged through
+/// `tokio_util::compat`
+async fn run_client_handshake<S>(
+    stream: S,
+    config: &HandshakeConfig,
+) -> Result<(impl AsyncRead + Send + Unpin, impl AsyncWrite + Send + Unpin), HandshakeError>
+where
+    S: AsyncRead + AsyncWrite + Send + Unpin,
+{
+    let mut compat = stream.compat();
+    let handshake = handshake_client(
+        &mut compat,
+        config.network_key.clone(),
+        config.public_key.clone(),
+        config.secret_key.clone(),
+        config.expected_peer_public_key.clone(),
+    )
+    .await?;
+    let boxed = BoxStream::new(compat, handshake, MAX_CHUNK_SIZE).compat();
+    Ok(tokio::io::split(boxed))
+}
+
+/// Maximum number of bytes of message payload carried by a single chunk on the wire.
+/// Bounds the size of any one allocation made while streaming a [crate::protocol::NetworkMessage]
+const MAX_CHUNK_SIZE: usize = 16 * 1024;
+
+/// Default cap on the total reassembled size of a single streamed message, guarding against
+/// a peer that never sends `is_last` (or sends an unbounded number of chunks) exhausting memory
+const DEFAULT_MAX_MESSAGE_SIZE: usize = 256 * 1024 * 1024;
+
+/// Default time a draining [Session] waits for [SessionWriter] to flush its pending queue
+/// before the writer is force-stopped
+const DEFAULT_DRAIN_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(5);
+
+/// Send priority for heartbeats and other small control traffic: always overtakes bulk sends
+pub const PRIORITY_HIGH: u8 = 255;
+/// Send priority used for ordinary RPC traffic when the caller has no preference
+pub const PRIORITY_NORMAL: u8 = 128;
+/// Send priority for large bulk transfers, which should yield to everything else in-flight
+pub const PRIORITY_LOW: u8 = 0;
+
+/// Wire size of a [ChunkHeader]: an 8-byte request id, a 1-byte priority, and a 1-byte
+/// `is_last` flag. The chunk's own length is carried by the surrounding
+/// [LengthDelimitedCodec] frame rather than duplicated in the header
+const CHUNK_HEADER_SIZE: usize = 8 + 1 + 1;
+
+/// Upper bound passed to [LengthDelimitedCodec] so a peer can't make us allocate an
+/// arbitrarily large buffer for a single frame
+const MAX_FRAME_LENGTH: usize = CHUNK_HEADER_SIZE + MAX_CHUNK_SIZE;
+
+/// Header prefixing every chunk frame on the wire. Chunks belonging to different outbound
+/// messages may be interleaved on the stream, so the header carries enough to demultiplex
+/// them back into whole messages on the reading side
+#[derive(Debug, Clone, Copy)]
+pub struct ChunkHeader {
+    /// Identifies which logical message this chunk reassembles into
+    request_id: u64,
+    /// Send priority the chunk was written with; informational on the reading side
+    priority: u8,
+    /// True if this is the last chunk of `request_id`
+    is_last: bool,
+}
+
+impl ChunkHeader {
+    fn to_bytes(self) -> [u8; CHUNK_HEADER_SIZE] {
+        let mut buf = [0u8; CHUNK_HEADER_SIZE];
+        buf[0..8].copy_from_slice(&self.request_id.to_be_bytes());
+        buf[8] = self.priority;
+        buf[9] = self.is_last as u8;
+        buf
+    }
+
+    fn from_bytes(buf: &[u8]) -> Self {
+        Self {
+            request_id: u64::from_be_bytes(buf[0..8].try_into().unwrap()),
+            priority: buf[8],
+            is_last: buf[9] != 0,
         }
-        c_len += n;
     }
-    Ok(buf)
+}
+
+/// Per-connection configuration applied when spawning a [Session]. The node-server accept
+/// loop builds one of these from its listener-level TLS/handshake settings and the outbound
+/// connect path builds one from its dialer-level settings, and both pass it straight through
+/// to [Session::spawn_linked] rather than threading the individual knobs as separate
+/// parameters. `Default` gives plaintext, unauthenticated sessions with the library defaults
+/// for message size and drain timeout, matching the behavior before TLS/handshake/drain support
+/// existed
+#[derive(Default)]
+pub struct SessionConnectionConfig {
+    /// How (or whether) to secure the underlying [TcpStream] with TLS
+    pub tls_mode: Option<SessionTlsMode>,
+    /// How (or whether) to run the Secret-Handshake mutual authentication exchange
+    pub handshake_mode: Option<SessionHandshakeMode>,
+    /// Cap on the total reassembled size of a single streamed message; defaults to
+    /// [DEFAULT_MAX_MESSAGE_SIZE]
+    pub max_message_size: Option<usize>,
+    /// How long a draining [Session] waits for [SessionWriter] to flush before being
+    /// force-stopped; defaults to [DEFAULT_DRAIN_TIMEOUT]
+    pub drain_timeout: Option<std::time::Duration>,
 }
 
 // ========================= Node Session actor ========================= //
 
 /// Represents a bi-directional tcp connection along with send + receive operations
 ///
-/// The [Session] actor supervises two child actors, [SessionReader] and [SessionWriter]. Should
-/// either the reader or writer exit, they will terminate the entire session.
+/// The [Session] actor supervises two child actors, [SessionReader] and [SessionWriter].
+/// When the reader exits (the peer stopped sending), the session stops accepting new
+/// [SessionMessage::Send] requests but lets [SessionWriter] drain whatever it has already
+/// queued, up to `drain_timeout`, before the writer and the session itself are stopped.
 pub struct Session {
     pub(crate) handler: ActorRef<crate::node::NodeSession>,
     pub(crate) peer_addr: SocketAddr,
     pub(crate) local_addr: SocketAddr,
+    pub(crate) drain_timeout: std::time::Duration,
 }
 
 impl Session {
@@ -61,15 +235,28 @@ impl Session {
         peer_addr: SocketAddr,
         local_addr: SocketAddr,
         supervisor: ActorCell,
+        config: SessionConnectionConfig,
     ) -> Result<ActorRef<Self>, SpawnErr> {
+        let SessionConnectionConfig {
+            tls_mode,
+            handshake_mode,
+            max_message_size,
+            drain_timeout,
+        } = config;
         match Actor::spawn_linked(
             None,
             Session {
                 handler,
                 peer_addr,
                 local_addr,
+                drain_timeout: drain_timeout.unwrap_or(DEFAULT_DRAIN_TIMEOUT),
+            },
+            SessionArguments {
+                stream,
+                tls_mode,
+                handshake_mode,
+                max_message_size: max_message_size.unwrap_or(DEFAULT_MAX_MESSAGE_SIZE),
             },
-            stream,
             supervisor,
         )
         .await
@@ -89,31 +276,130 @@ impl Session {
 /// The node connection messages
 #[derive(RactorMessage)]
 pub enum SessionMessage {
-    /// Send a message over the channel
-    Send(crate::protocol::NetworkMessage),
+    /// Send a message over the channel at the given priority (see [PRIORITY_HIGH],
+    /// [PRIORITY_NORMAL], [PRIORITY_LOW])
+    Send(crate::protocol::NetworkMessage, u8),
 
     /// An object was received on the channel
     ObjectAvailable(crate::protocol::NetworkMessage),
+
+    /// The reader side has closed; stop accepting new sends and let the writer drain
+    /// whatever it already has queued before the session exits
+    BeginDrain,
+
+    /// `drain_timeout` elapsed since [SessionMessage::BeginDrain] without the writer
+    /// finishing on its own; force the session closed
+    DrainTimeout,
+}
+
+/// Arguments needed to start a [Session]: the accepted/connected stream, plus
+/// an optional TLS mode to negotiate before any [SessionMessage] traffic flows
+pub struct SessionArguments {
+    stream: TcpStream,
+    tls_mode: Option<SessionTlsMode>,
+    handshake_mode: Option<SessionHandshakeMode>,
+    /// Cap on the total reassembled size of any single streamed message
+    max_message_size: usize,
 }
 
 /// The node session's state
 pub struct SessionState {
     writer: ActorRef<SessionWriter>,
     reader: ActorRef<SessionReader>,
+    /// Set once the reader has exited; new [SessionMessage::Send] requests are refused and
+    /// we're just waiting for [SessionWriter] to drain
+    draining: bool,
 }
 
 #[async_trait::async_trait]
 impl Actor for Session {
     type Msg = SessionMessage;
-    type Arguments = TcpStream;
+    type Arguments = SessionArguments;
     type State = SessionState;
 
     async fn pre_start(
         &self,
         myself: ActorRef<Self>,
-        stream: TcpStream,
+        args: SessionArguments,
     ) -> Result<Self::State, ActorProcessingErr> {
-        let (read, write) = stream.into_split();
+        let SessionArguments {
+            stream,
+            tls_mode,
+            handshake_mode,
+            max_message_size,
+        } = args;
+
+        let (read, write): (SessionReadHalf, SessionWriteHalf) = match tls_mode {
+            None => {
+                let (read, write) = stream.into_split();
+                (Box::new(read), Box::new(write))
+            }
+            Some(SessionTlsMode::Server(server_config)) => {
+                let tls_stream = TlsAcceptor::from(server_config)
+                    .accept(stream)
+                    .await
+                    .map_err(|err| {
+                        log::warn!(
+                            "TLS handshake with {} failed (server side): {}",
+                            self.peer_addr,
+                            err
+                        );
+                        err
+                    })?;
+                let (read, write) = tokio::io::split(tls_stream);
+                (Box::new(read), Box::new(write))
+            }
+            Some(SessionTlsMode::Client(client_config, server_name)) => {
+                let tls_stream = TlsConnector::from(client_config)
+                    .connect(server_name, stream)
+                    .await
+                    .map_err(|err| {
+                        log::warn!(
+                            "TLS handshake with {} failed (client side): {}",
+                            self.peer_addr,
+                            err
+                        );
+                        err
+                    })?;
+                let (read, write) = tokio::io::split(tls_stream);
+                (Box::new(read), Box::new(write))
+            }
+        };
+
+        // Before trusting any bytes on this connection, optionally run the Secret-Handshake
+        // mutual authentication exchange over the (possibly already TLS-wrapped) stream
+        let (read, write): (SessionReadHalf, SessionWriteHalf) = match handshake_mode {
+            None => (read, write),
+            Some(SessionHandshakeMode::Server(config)) => {
+                let joined = tokio::io::join(read, write);
+                let (read, write) = run_server_handshake(joined, config.as_ref())
+                    .await
+                    .map_err(|err| {
+                        log::warn!(
+                            "Secret-Handshake with {} failed (server side): {}",
+                            self.peer_addr,
+                            err
+                        );
+                        err
+                    })?;
+                (Box::new(read), Box::new(write))
+            }
+            Some(SessionHandshakeMode::Client(config)) => {
+                let joined = tokio::io::join(read, write);
+                let (read, write) = run_client_handshake(joined, config.as_ref())
+                    .await
+                    .map_err(|err| {
+                        log::warn!(
+                            "Secret-Handshake with {} failed (client side): {}",
+                            self.peer_addr,
+                            err
+                        );
+                        err
+                    })?;
+                (Box::new(read), Box::new(write))
+            }
+        };
+
         // spawn writer + reader child actors
         let (writer, _) =
             Actor::spawn_linked(None, SessionWriter, write, myself.get_cell()).await?;
@@ -121,13 +407,18 @@ impl Actor for Session {
             None,
             SessionReader {
                 session: myself.clone(),
+                max_message_size,
             },
             read,
             myself.get_cell(),
         )
         .await?;
 
-        Ok(Self::State { writer, reader })
+        Ok(Self::State {
+            writer,
+            reader,
+            draining: false,
+        })
     }
 
     async fn post_stop(
@@ -141,19 +432,29 @@ impl Actor for Session {
 
     async fn handle(
         &self,
-        _myself: ActorRef<Self>,
+        myself: ActorRef<Self>,
         message: Self::Msg,
         state: &mut Self::State,
     ) -> Result<(), ActorProcessingErr> {
         match message {
-            Self::Msg::Send(msg) => {
+            Self::Msg::Send(msg, priority) if state.draining => {
+                log::debug!(
+                    "Dropping outbound message to {} - session is draining: '{:?}'",
+                    self.peer_addr,
+                    msg
+                );
+                let _ = priority;
+            }
+            Self::Msg::Send(msg, priority) => {
                 log::debug!(
                     "SEND: {} -> {} - '{:?}'",
                     self.local_addr,
                     self.peer_addr,
                     msg
                 );
-                let _ = state.writer.cast(SessionWriterMessage::WriteObject(msg));
+                let _ = state
+                    .writer
+                    .cast(SessionWriterMessage::WriteObject(msg, priority));
             }
             Self::Msg::ObjectAvailable(msg) => {
                 log::debug!(
@@ -166,6 +467,27 @@ impl Actor for Session {
                     .handler
                     .cast(crate::node::NodeSessionMessage::MessageReceived(msg));
             }
+            Self::Msg::BeginDrain if !state.draining => {
+                log::debug!(
+                    "TCP Session to {} draining outstanding writes before close",
+                    self.peer_addr
+                );
+                state.draining = true;
+                let _ = state.writer.cast(SessionWriterMessage::Drain);
+                myself.send_after(self.drain_timeout, || SessionMessage::DrainTimeout);
+            }
+            Self::Msg::BeginDrain => {
+                // already draining, nothing to do
+            }
+            Self::Msg::DrainTimeout => {
+                if state.draining {
+                    log::warn!(
+                        "TCP Session to {} did not drain within the timeout, force-closing",
+                        self.peer_addr
+                    );
+                    myself.stop(Some("drain_timeout".to_string()));
+                }
+            }
         }
         Ok(())
     }
@@ -191,13 +513,15 @@ impl Actor for Session {
             }
             SupervisionEvent::ActorTerminated(actor, _, exit_reason) => {
                 if actor.get_id() == state.reader.get_id() {
-                    log::debug!("TCP Session's reader exited");
+                    log::debug!("TCP Session's reader exited, beginning drain");
+                    let _ = myself.cast(SessionMessage::BeginDrain);
                 } else if actor.get_id() == state.writer.get_id() {
                     log::debug!("TCP Session's writer exited");
+                    myself.stop(Some("child_terminate".to_string()));
                 } else {
                     log::warn!("TCP Session received a child exit from an unknown child actor ({}) - '{:?}'", actor.get_id(), exit_reason);
+                    myself.stop(Some("child_terminate".to_string()));
                 }
-                myself.stop(Some("child_terminate".to_string()));
             }
             _ => {
                 // all ok
@@ -211,31 +535,105 @@ impl Actor for Session {
 
 struct SessionWriter;
 
+/// A message queued for send, broken into not-yet-written chunks in send order
+struct PendingSend {
+    request_id: u64,
+    /// Remaining chunks to write, front-to-back
+    chunks: std::collections::VecDeque<Vec<u8>>,
+}
+
 struct SessionWriterState {
-    writer: Option<OwnedWriteHalf>,
+    writer: Option<FramedWrite<SessionWriteHalf, LengthDelimitedCodec>>,
+    /// Monotonically increasing id handed out to the next streamed message
+    next_request_id: u64,
+    /// Messages waiting to be written, bucketed by priority. Iterated highest-priority-first
+    /// so a message enqueued with [PRIORITY_HIGH] overtakes one already mid-flight at
+    /// [PRIORITY_LOW] as soon as the low-priority send yields between chunks
+    queues: std::collections::BTreeMap<u8, std::collections::VecDeque<PendingSend>>,
+    /// True while a `SendNextChunk` is outstanding, so we don't schedule a second send loop
+    sending: bool,
+    /// Set once [SessionWriterMessage::Drain] is received; once the queues run dry the
+    /// writer stops itself instead of idling
+    draining: bool,
+}
+
+impl SessionWriterState {
+    /// Pop the next chunk to write from the highest-priority non-empty queue, along with
+    /// the frame metadata it should be written with and whether any queue still has work
+    fn pop_next_chunk(&mut self) -> Option<(ChunkHeader, Vec<u8>, bool)> {
+        let (&priority, queue) = self
+            .queues
+            .iter_mut()
+            .rev()
+            .find(|(_, queue)| !queue.is_empty())?;
+        let pending = queue.front_mut()?;
+        let request_id = pending.request_id;
+        let chunk = match pending.chunks.pop_front() {
+            Some(chunk) => chunk,
+            None => {
+                // WriteObject always enqueues at least one chunk, so this should be
+                // unreachable, but drop the empty entry and move on rather than stalling
+                // every other queue behind it
+                queue.pop_front();
+                self.queues.retain(|_, queue| !queue.is_empty());
+                return self.pop_next_chunk();
+            }
+        };
+        let is_last = pending.chunks.is_empty();
+        if is_last {
+            queue.pop_front();
+        }
+        self.queues.retain(|_, queue| !queue.is_empty());
+
+        let header = ChunkHeader {
+            request_id,
+            priority,
+            is_last,
+        };
+        let more_work = self.queues.values().any(|queue| !queue.is_empty());
+        Some((header, chunk, more_work))
+    }
+
+    /// True once a drain has been requested and every queued send has been written,
+    /// meaning the writer can stop itself instead of sitting idle
+    fn is_drained(&self) -> bool {
+        self.draining && self.queues.values().all(|queue| queue.is_empty())
+    }
 }
 
 #[derive(crate::RactorMessage)]
 enum SessionWriterMessage {
-    /// Write an object over the wire
-    WriteObject(crate::protocol::NetworkMessage),
+    /// Write an object over the wire at the given priority
+    WriteObject(crate::protocol::NetworkMessage, u8),
+    /// Write the next chunk from the highest-priority non-empty send queue
+    SendNextChunk,
+    /// No more [SessionWriterMessage::WriteObject]s will arrive; flush whatever is
+    /// already queued and then stop
+    Drain,
 }
 
 #[async_trait::async_trait]
 impl Actor for SessionWriter {
     type Msg = SessionWriterMessage;
-    type Arguments = OwnedWriteHalf;
+    type Arguments = SessionWriteHalf;
     type State = SessionWriterState;
 
     async fn pre_start(
         &self,
         _myself: ActorRef<Self>,
-        writer: OwnedWriteHalf,
+        writer: SessionWriteHalf,
     ) -> Result<Self::State, ActorProcessingErr> {
         // OK we've established connection, now we can process requests
+        let framed = LengthDelimitedCodec::builder()
+            .max_frame_length(MAX_FRAME_LENGTH)
+            .new_write(writer);
 
         Ok(Self::State {
-            writer: Some(writer),
+            writer: Some(framed),
+            next_request_id: 0,
+            queues: std::collections::BTreeMap::new(),
+            sending: false,
+            draining: false,
         })
     }
 
@@ -256,31 +654,80 @@ impl Actor for SessionWriter {
         state: &mut Self::State,
     ) -> Result<(), ActorProcessingErr> {
         match message {
-            SessionWriterMessage::WriteObject(msg) if state.writer.is_some() => {
+            SessionWriterMessage::WriteObject(msg, priority) => {
+                let request_id = state.next_request_id;
+                state.next_request_id = state.next_request_id.wrapping_add(1);
+
+                let encoded_data = msg.encode_to_vec();
+                // a zero-length message still needs exactly one (empty, `is_last`) chunk
+                let mut chunks: std::collections::VecDeque<Vec<u8>> = if encoded_data.is_empty() {
+                    std::collections::VecDeque::from([Vec::new()])
+                } else {
+                    encoded_data
+                        .chunks(MAX_CHUNK_SIZE)
+                        .map(|c| c.to_vec())
+                        .collect()
+                };
+                state
+                    .queues
+                    .entry(priority)
+                    .or_default()
+                    .push_back(PendingSend { request_id, chunks });
+
+                if !state.sending {
+                    state.sending = true;
+                    let _ = myself.cast(SessionWriterMessage::SendNextChunk);
+                }
+            }
+            SessionWriterMessage::SendNextChunk if state.writer.is_some() => {
+                let Some((header, chunk, more_work)) = state.pop_next_chunk() else {
+                    state.sending = false;
+                    if state.is_drained() {
+                        log::debug!("TCP Session writer finished draining, stopping");
+                        myself.stop(Some("drained".to_string()));
+                    }
+                    return Ok(());
+                };
+
                 if let Some(stream) = &mut state.writer {
-                    stream.writable().await.unwrap();
-
-                    let encoded_data = msg.encode_length_delimited_to_vec();
-                    let length = encoded_data.len();
-                    let length_bytes: [u8; 8] = (length as u64).to_be_bytes();
-                    log::trace!("Writing 8 length bytes");
-                    if let Err(write_err) = stream.write_all(&length_bytes).await {
-                        log::warn!("Error writing to the stream '{}'", write_err);
+                    let mut frame = Vec::with_capacity(CHUNK_HEADER_SIZE + chunk.len());
+                    frame.extend_from_slice(&header.to_bytes());
+                    frame.extend_from_slice(&chunk);
+                    // Only flush once this chunk finishes its message or the send queues run
+                    // dry; a multi-chunk bulk transfer otherwise just feeds each chunk into
+                    // the codec's write buffer so it doesn't pay a flush-sized syscall per
+                    // 16 KiB chunk
+                    let write_result = if header.is_last || !more_work {
+                        stream.send(Bytes::from(frame)).await
                     } else {
-                        log::trace!("Wrote length, writing payload (len={})", length);
-                        // now send the object
-                        if let Err(write_err) = stream.write_all(&encoded_data).await {
-                            log::warn!("Error writing to the stream '{}'", write_err);
-                            myself.stop(Some("channel_closed".to_string()));
-                            return Ok(());
-                        }
-                        // flush the stream
-                        stream.flush().await.unwrap();
+                        stream.feed(Bytes::from(frame)).await
+                    };
+                    if let Err(write_err) = write_result {
+                        log::warn!("Error writing chunk frame to the stream '{}'", write_err);
+                        myself.stop(Some("channel_closed".to_string()));
+                        return Ok(());
                     }
                 }
+
+                state.sending = more_work;
+                if more_work {
+                    // re-cast (rather than loop in place) so a higher-priority WriteObject
+                    // queued in the meantime is picked up on the very next iteration
+                    let _ = myself.cast(SessionWriterMessage::SendNextChunk);
+                } else if state.is_drained() {
+                    log::debug!("TCP Session writer finished draining, stopping");
+                    myself.stop(Some("drained".to_string()));
+                }
+            }
+            SessionWriterMessage::Drain => {
+                state.draining = true;
+                if state.is_drained() {
+                    // nothing queued (or nothing left to flush) - stop right away
+                    myself.stop(Some("drained".to_string()));
+                }
             }
             _ => {
-                // no-op, wait for next send request
+                // no-op, e.g. no stream available to send on
             }
         }
         Ok(())
@@ -291,51 +738,167 @@ impl Actor for SessionWriter {
 
 struct SessionReader {
     session: ActorRef<Session>,
+    /// Cap on the total reassembled size of any single streamed message
+    max_message_size: usize,
 }
 
+/// Cap on the number of distinct `request_id`s that may be mid-reassembly at once. Without
+/// this, a peer that opens an unbounded number of `request_id`s and sends a single small
+/// chunk to each (never sending `is_last`) could grow [SessionReaderState::partial] without
+/// limit even though each individual message stays under `max_message_size`
+const MAX_IN_FLIGHT_PARTIALS: usize = 1024;
+
 /// The node connection messages
 pub enum SessionReaderMessage {
-    /// Wait for an object from the stream
-    WaitForObject,
-
-    /// Read next object off the stream
-    ReadObject(u64),
+    /// Read and process the next frame off the stream, then re-cast this to itself to
+    /// continue once that frame has been fully handled. This keeps at most one frame read
+    /// ahead of processing - the same lockstep backpressure the old `WaitForObject`/
+    /// `ReadObject` loop gave us - instead of a detached pump racing ahead of the mailbox
+    ReadNextFrame,
 }
 
 impl ractor::Message for SessionReaderMessage {}
 
 struct SessionReaderState {
-    reader: Option<OwnedReadHalf>,
+    /// In-flight messages, keyed by `request_id`, accumulating chunks until `is_last`
+    partial: std::collections::HashMap<u64, Vec<u8>>,
+    /// The stream we decode chunk frames from, driven directly from the actor's own `handle`
+    framed: FramedRead<SessionReadHalf, LengthDelimitedCodec>,
+}
+
+/// What to do with a decoded chunk frame once it's been folded into [SessionReaderState::partial]
+#[derive(Debug, PartialEq, Eq)]
+enum ReassemblyOutcome {
+    /// `request_id` isn't done yet; still waiting on more chunks
+    Pending,
+    /// `is_last` was set; the fully reassembled message bytes for `request_id`
+    Complete(Vec<u8>),
+}
+
+/// Why [SessionReaderState::accumulate] refused a chunk; either one means the session should
+/// be aborted rather than let the peer keep growing our memory usage
+#[derive(Debug, PartialEq, Eq)]
+enum ReassemblyError {
+    /// `request_id`'s reassembled size exceeded `max_message_size`
+    MessageTooLarge,
+    /// Too many distinct `request_id`s are mid-reassembly at once (see [MAX_IN_FLIGHT_PARTIALS])
+    TooManyInFlightMessages,
+}
+
+impl SessionReaderState {
+    /// Folds one decoded chunk's payload into the reassembly buffer for `header.request_id`,
+    /// enforcing both the per-message size cap and the cap on the number of concurrently
+    /// in-flight `request_id`s
+    fn accumulate(
+        &mut self,
+        header: ChunkHeader,
+        payload: &[u8],
+        max_message_size: usize,
+    ) -> Result<ReassemblyOutcome, ReassemblyError> {
+        if !self.partial.contains_key(&header.request_id)
+            && self.partial.len() >= MAX_IN_FLIGHT_PARTIALS
+        {
+            return Err(ReassemblyError::TooManyInFlightMessages);
+        }
+
+        let entry = self.partial.entry(header.request_id).or_default();
+        entry.extend_from_slice(payload);
+
+        if entry.len() > max_message_size {
+            self.partial.remove(&header.request_id);
+            return Err(ReassemblyError::MessageTooLarge);
+        }
+
+        if header.is_last {
+            Ok(ReassemblyOutcome::Complete(
+                self.partial.remove(&header.request_id).unwrap_or_default(),
+            ))
+        } else {
+            Ok(ReassemblyOutcome::Pending)
+        }
+    }
+}
+
+impl SessionReader {
+    /// Decodes one chunk frame, folds it into `state`'s reassembly buffers, and forwards
+    /// or aborts as appropriate. Pulled out of `handle` so the `ReadNextFrame` arm reads
+    /// as "read a frame, react to it, ask for the next one"
+    fn handle_frame(&self, myself: &ActorRef<Self>, state: &mut SessionReaderState, frame: BytesMut) {
+        if frame.len() < CHUNK_HEADER_SIZE {
+            log::error!("Received a chunk frame shorter than the chunk header, discarding");
+            return;
+        }
+        let header = ChunkHeader::from_bytes(&frame[..CHUNK_HEADER_SIZE]);
+        let payload = &frame[CHUNK_HEADER_SIZE..];
+        log::trace!(
+            "Chunk (request_id={}, len={}, is_last={}) received",
+            header.request_id,
+            payload.len(),
+            header.is_last
+        );
+
+        match state.accumulate(header, payload, self.max_message_size) {
+            Ok(ReassemblyOutcome::Pending) => {}
+            Ok(ReassemblyOutcome::Complete(complete)) => {
+                let bytes = Bytes::from(complete);
+                match crate::protocol::NetworkMessage::decode(bytes) {
+                    Ok(msg) => {
+                        let _ = self.session.cast(SessionMessage::ObjectAvailable(msg));
+                    }
+                    Err(decode_err) => {
+                        log::error!(
+                            "Error decoding network message: '{}'. Discarding",
+                            decode_err
+                        );
+                    }
+                }
+            }
+            Err(ReassemblyError::MessageTooLarge) => {
+                log::error!(
+                    "Streamed message {} exceeded the maximum size of {} bytes, aborting session",
+                    header.request_id,
+                    self.max_message_size
+                );
+                myself.stop(Some("message_too_large".to_string()));
+            }
+            Err(ReassemblyError::TooManyInFlightMessages) => {
+                log::error!(
+                    "Peer has too many streamed messages mid-reassembly at once (limit {}), aborting session",
+                    MAX_IN_FLIGHT_PARTIALS
+                );
+                myself.stop(Some("too_many_in_flight_messages".to_string()));
+            }
+        }
+    }
 }
 
 #[async_trait::async_trait]
 impl Actor for SessionReader {
     type Msg = SessionReaderMessage;
-    type Arguments = OwnedReadHalf;
+    type Arguments = SessionReadHalf;
     type State = SessionReaderState;
 
     async fn pre_start(
         &self,
         myself: ActorRef<Self>,
-        reader: OwnedReadHalf,
+        reader: SessionReadHalf,
     ) -> Result<Self::State, ActorProcessingErr> {
-        // start waiting for the first object on the network
-        let _ = myself.cast(SessionReaderMessage::WaitForObject);
+        let framed = LengthDelimitedCodec::builder()
+            .max_frame_length(MAX_FRAME_LENGTH)
+            .new_read(reader);
+
+        // Kick off the read loop. Each frame is read and fully processed, inside this
+        // actor's own handle(), before the next ReadNextFrame is cast - ractor's
+        // supervision covers the await just like any other message, and the stream can
+        // never get more than one frame ahead of processing
+        myself.cast(SessionReaderMessage::ReadNextFrame)?;
+
         Ok(Self::State {
-            reader: Some(reader),
+            partial: std::collections::HashMap::new(),
+            framed,
         })
     }
 
-    async fn post_stop(
-        &self,
-        _myself: ActorRef<Self>,
-        state: &mut Self::State,
-    ) -> Result<(), ActorProcessingErr> {
-        // drop the channel to close it should we be exiting
-        drop(state.reader.take());
-        Ok(())
-    }
-
     async fn handle(
         &self,
         myself: ActorRef<Self>,
@@ -343,74 +906,263 @@ impl Actor for SessionReader {
         state: &mut Self::State,
     ) -> Result<(), ActorProcessingErr> {
         match message {
-            Self::Msg::WaitForObject if state.reader.is_some() => {
-                if let Some(stream) = &mut state.reader {
-                    match read_n_bytes(stream, 8).await {
-                        Ok(buf) => {
-                            let length = u64::from_be_bytes(buf.try_into().unwrap());
-                            log::trace!("Payload length message ({}) received", length);
-                            let _ = myself.cast(SessionReaderMessage::ReadObject(length));
-                            return Ok(());
-                        }
-                        Err(err) if err.kind() == ErrorKind::UnexpectedEof => {
-                            log::trace!("Error (EOF) on stream");
-                            // EOF, close the stream by dropping the stream
-                            drop(state.reader.take());
-                            myself.stop(Some("channel_closed".to_string()));
-                        }
-                        Err(_other_err) => {
-                            log::trace!("Error ({:?}) on stream", _other_err);
-                            // some other TCP error, more handling necessary
-                        }
-                    }
+            Self::Msg::ReadNextFrame => match state.framed.next().await {
+                Some(Ok(frame)) => {
+                    self.handle_frame(&myself, state, frame);
+                    myself.cast(SessionReaderMessage::ReadNextFrame)?;
                 }
-
-                let _ = myself.cast(SessionReaderMessage::WaitForObject);
-            }
-            Self::Msg::ReadObject(length) if state.reader.is_some() => {
-                if let Some(stream) = &mut state.reader {
-                    match read_n_bytes(stream, length as usize).await {
-                        Ok(buf) => {
-                            log::trace!("Payload of length({}) received", buf.len());
-                            // NOTE: Our implementation writes 2 messages when sending something over the wire, the first
-                            // is exactly 8 bytes which constitute the length of the payload message (u64 in big endian format),
-                            // followed by the payload. This tells our TCP reader how much data to read off the wire
-
-                            // [buf] here should contain the exact amount of data to decode an object properly.
-                            let bytes = Bytes::from(buf);
-                            match crate::protocol::NetworkMessage::decode_length_delimited(bytes) {
-                                Ok(msg) => {
-                                    // we decoded a message, pass it up the chain
-                                    let _ = self.session.cast(SessionMessage::ObjectAvailable(msg));
-                                }
-                                Err(decode_err) => {
-                                    log::error!(
-                                        "Error decoding network message: '{}'. Discarding",
-                                        decode_err
-                                    );
-                                }
-                            }
-                        }
-                        Err(err) if err.kind() == ErrorKind::UnexpectedEof => {
-                            // EOF, close the stream by dropping the stream
-                            drop(state.reader.take());
-                            myself.stop(Some("channel_closed".to_string()));
-                            return Ok(());
-                        }
-                        Err(_other_err) => {
-                            // TODO: some other TCP error, more handling necessary
-                        }
-                    }
+                Some(Err(err)) => {
+                    log::warn!("Error reading a frame off the stream '{}'", err);
+                    myself.stop(Some("channel_closed".to_string()));
                 }
+                None => {
+                    log::trace!("Stream closed (EOF)");
+                    myself.stop(Some("channel_closed".to_string()));
+                }
+            },
+        }
+        Ok(())
+    }
+}
 
-                // we've read the object, now wait for next object
-                let _ = myself.cast(SessionReaderMessage::WaitForObject);
-            }
-            _ => {
-                // no stream is available, keep looping until one is available
-                let _ = myself.cast(SessionReaderMessage::WaitForObject);
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header(request_id: u64, is_last: bool) -> ChunkHeader {
+        ChunkHeader {
+            request_id,
+            priority: PRIORITY_NORMAL,
+            is_last,
+        }
+    }
+
+    /// These tests exercise `SessionReaderState::accumulate` directly and never touch
+    /// `framed`, so an empty stream is enough to satisfy the field
+    fn reader_state() -> SessionReaderState {
+        let framed: SessionReadHalf = Box::new(tokio::io::empty());
+        SessionReaderState {
+            partial: std::collections::HashMap::new(),
+            framed: LengthDelimitedCodec::builder()
+                .max_frame_length(MAX_FRAME_LENGTH)
+                .new_read(framed),
+        }
+    }
+
+    #[test]
+    fn reassembles_interleaved_request_ids() {
+        let mut state = reader_state();
+
+        // two messages' chunks arrive interleaved on the wire
+        assert!(matches!(
+            state.accumulate(header(1, false), b"hello-", 1024),
+            Ok(ReassemblyOutcome::Pending)
+        ));
+        assert!(matches!(
+            state.accumulate(header(2, false), b"goodbye-", 1024),
+            Ok(ReassemblyOutcome::Pending)
+        ));
+        assert!(matches!(
+            state.accumulate(header(1, true), b"world", 1024),
+            Ok(ReassemblyOutcome::Complete(bytes)) if bytes == b"hello-world".to_vec()
+        ));
+        assert!(matches!(
+            state.accumulate(header(2, true), b"cruel-world", 1024),
+            Ok(ReassemblyOutcome::Complete(bytes)) if bytes == b"goodbye-cruel-world".to_vec()
+        ));
+        assert!(state.partial.is_empty());
+    }
+
+    #[test]
+    fn aborts_when_a_single_message_exceeds_the_size_cap() {
+        let mut state = reader_state();
+
+        assert_eq!(
+            state.accumulate(header(1, false), &[0u8; 16], 8),
+            Err(ReassemblyError::MessageTooLarge)
+        );
+        // the oversized message's partial buffer is dropped, not left around
+        assert!(state.partial.is_empty());
+    }
+
+    #[test]
+    fn aborts_when_too_many_messages_are_mid_reassembly_at_once() {
+        let mut state = reader_state();
+
+        for request_id in 0..MAX_IN_FLIGHT_PARTIALS as u64 {
+            assert!(matches!(
+                state.accumulate(header(request_id, false), b"x", 1024),
+                Ok(ReassemblyOutcome::Pending)
+            ));
+        }
+
+        // a never-completing message never goes away on its own; one more distinct
+        // request_id than the cap allows should abort the session rather than grow forever
+        assert_eq!(
+            state.accumulate(
+                header(MAX_IN_FLIGHT_PARTIALS as u64, false),
+                b"x",
+                1024
+            ),
+            Err(ReassemblyError::TooManyInFlightMessages)
+        );
+    }
+
+    fn writer_state() -> SessionWriterState {
+        SessionWriterState {
+            writer: None,
+            next_request_id: 0,
+            queues: std::collections::BTreeMap::new(),
+            sending: false,
+            draining: false,
+        }
+    }
+
+    fn enqueue(state: &mut SessionWriterState, priority: u8, request_id: u64, chunks: &[&[u8]]) {
+        state.queues.entry(priority).or_default().push_back(PendingSend {
+            request_id,
+            chunks: chunks.iter().map(|c| c.to_vec()).collect(),
+        });
+    }
+
+    #[test]
+    fn high_priority_overtakes_a_low_priority_transfer_mid_flight() {
+        let mut state = writer_state();
+
+        // a large low-priority bulk transfer, several chunks long
+        enqueue(
+            &mut state,
+            PRIORITY_LOW,
+            1,
+            &[b"bulk-chunk-1", b"bulk-chunk-2", b"bulk-chunk-3"],
+        );
+
+        // the first popped chunk is the low-priority transfer's, since nothing else is queued
+        let (header, chunk, more_work) = state.pop_next_chunk().expect("chunk available");
+        assert_eq!(header.request_id, 1);
+        assert_eq!(chunk, b"bulk-chunk-1".to_vec());
+        assert!(more_work);
+
+        // a heartbeat arrives mid-transfer, queued at high priority
+        enqueue(&mut state, PRIORITY_HIGH, 2, &[b"heartbeat"]);
+
+        // it overtakes the rest of the bulk transfer rather than waiting behind it
+        let (header, chunk, more_work) = state.pop_next_chunk().expect("chunk available");
+        assert_eq!(header.request_id, 2);
+        assert_eq!(chunk, b"heartbeat".to_vec());
+        assert!(more_work);
+
+        // only once the high-priority queue is drained does the bulk transfer resume, in order
+        let (header, chunk, _) = state.pop_next_chunk().expect("chunk available");
+        assert_eq!(header.request_id, 1);
+        assert_eq!(chunk, b"bulk-chunk-2".to_vec());
+        let (header, chunk, more_work) = state.pop_next_chunk().expect("chunk available");
+        assert_eq!(header.request_id, 1);
+        assert_eq!(chunk, b"bulk-chunk-3".to_vec());
+        assert!(!more_work);
+    }
+
+    #[test]
+    fn send_queue_fully_drains_before_reporting_no_more_work() {
+        let mut state = writer_state();
+        enqueue(&mut state, PRIORITY_LOW, 1, &[b"a", b"b"]);
+        enqueue(&mut state, PRIORITY_NORMAL, 2, &[b"c"]);
+
+        let mut popped = Vec::new();
+        while let Some((header, chunk, more_work)) = state.pop_next_chunk() {
+            popped.push((header.request_id, chunk));
+            if !more_work {
+                break;
             }
         }
-        Ok(())
+
+        assert_eq!(
+            popped,
+            vec![
+                (2, b"c".to_vec()),
+                (1, b"a".to_vec()),
+                (1, b"b".to_vec()),
+            ]
+        );
+        assert!(state.queues.values().all(|q| q.is_empty()));
+    }
+
+    #[test]
+    fn pop_next_chunk_skips_a_malformed_empty_pending_send_instead_of_stalling() {
+        let mut state = writer_state();
+
+        // this should never happen per the enqueue-time invariant, but if an empty
+        // PendingSend ever ends up at the front of a queue, it must not stall every
+        // other queue behind it
+        state
+            .queues
+            .entry(PRIORITY_NORMAL)
+            .or_default()
+            .push_back(PendingSend {
+                request_id: 1,
+                chunks: std::collections::VecDeque::new(),
+            });
+        enqueue(&mut state, PRIORITY_LOW, 2, &[b"a"]);
+
+        let (header, chunk, more_work) = state.pop_next_chunk().expect("chunk available");
+        assert_eq!(header.request_id, 2);
+        assert_eq!(chunk, b"a".to_vec());
+        assert!(!more_work);
+        assert!(state.queues.values().all(|q| q.is_empty()));
+    }
+
+    #[tokio::test]
+    async fn chunk_frames_round_trip_over_a_length_delimited_codec() {
+        let (client, server) = tokio::io::duplex(4096);
+
+        let mut writer = LengthDelimitedCodec::builder()
+            .max_frame_length(MAX_FRAME_LENGTH)
+            .new_write(client);
+        let mut reader = LengthDelimitedCodec::builder()
+            .max_frame_length(MAX_FRAME_LENGTH)
+            .new_read(server);
+
+        let sent = header(42, true);
+        let mut frame = Vec::with_capacity(CHUNK_HEADER_SIZE + 5);
+        frame.extend_from_slice(&sent.to_bytes());
+        frame.extend_from_slice(b"hello");
+        writer.send(Bytes::from(frame)).await.unwrap();
+
+        let received = reader.next().await.unwrap().unwrap();
+        let got = ChunkHeader::from_bytes(&received[..CHUNK_HEADER_SIZE]);
+        assert_eq!(got.request_id, sent.request_id);
+        assert_eq!(got.priority, sent.priority);
+        assert_eq!(got.is_last, sent.is_last);
+        assert_eq!(&received[CHUNK_HEADER_SIZE..], b"hello");
+    }
+
+    #[test]
+    fn drain_waits_for_the_queue_to_empty_before_reporting_drained() {
+        let mut state = writer_state();
+        enqueue(&mut state, PRIORITY_LOW, 1, &[b"a", b"b"]);
+
+        // a drain is requested mid-queue: there's still pending work, so it must not stop yet
+        state.draining = true;
+        assert!(!state.is_drained());
+
+        // the queue hasn't been flushed yet
+        let (_, _, more_work) = state.pop_next_chunk().expect("chunk available");
+        assert!(more_work);
+        assert!(!state.is_drained());
+
+        // once every queued chunk has been written, the drain is complete
+        let (_, _, more_work) = state.pop_next_chunk().expect("chunk available");
+        assert!(!more_work);
+        assert!(state.is_drained());
+    }
+
+    #[test]
+    fn drain_with_nothing_queued_completes_immediately() {
+        let state = writer_state();
+        assert!(!state.is_drained(), "not draining yet");
+
+        let mut state = state;
+        state.draining = true;
+        assert!(state.is_drained());
     }
 }